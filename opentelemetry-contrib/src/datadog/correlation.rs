@@ -0,0 +1,27 @@
+//! Trace/log correlation.
+//!
+//! Datadog's log correlation feature matches log lines to traces via
+//! `dd.trace_id`/`dd.span_id` fields, which are 64-bit unsigned decimal
+//! values rather than OTel's 128-bit trace id or hex formatting.
+
+use opentelemetry::api::trace::TraceContextExt;
+use opentelemetry::api::Context;
+
+/// The active span's trace/span ids in Datadog's `dd.trace_id`/`dd.span_id`
+/// log correlation form.
+///
+/// `trace_id` is the low 64 bits of the OTel 128-bit `TraceId`, and
+/// `span_id` is the OTel `SpanId` as-is, both returned as `u64`s whose
+/// `Display` output is the unsigned decimal form Datadog expects in log
+/// fields. Returns `None` if `cx` has no valid current span.
+pub fn log_correlation_ids(cx: &Context) -> Option<(u64, u64)> {
+    let span_context = cx.span().span_context();
+    if !span_context.is_valid() {
+        return None;
+    }
+
+    Some((
+        span_context.trace_id().to_u128() as u64,
+        span_context.span_id().to_u64(),
+    ))
+}
@@ -31,22 +31,33 @@
 //!
 //! For standard values see here - https://github.com/DataDog/dd-trace-go/blob/ecb0b805ef25b00888a2fb62d465a5aa95e7301e/ddtrace/ext/app_types.go#L31
 //!
+//! ## Propagation
+//!
+//! This crate exposes [`DatadogPropagator`], a [`TextMapPropagator`] that
+//! reads and writes Datadog's `x-datadog-*` headers, for services that need
+//! to maintain a trace across a boundary with a peer speaking the Datadog
+//! propagation format rather than W3C trace context. Register it with
+//! [`global::set_text_map_propagator`] alongside, or instead of, the default
+//! propagator.
+//!
+//! [`TextMapPropagator`]: opentelemetry::api::TextMapPropagator
+//! [`global::set_text_map_propagator`]: opentelemetry::global::set_text_map_propagator
+//!
 //! ## Performance
 //!
 //! For optimal performance, a batch exporter is recommended as the simple
-//! exporter will export each span synchronously on drop. You can enable the
-//! [`tokio`] or [`async-std`] features to have a batch exporter configured for
-//! you automatically for either executor when you install the pipeline.
+//! exporter will export each span synchronously on drop. Use
+//! [`install_batch`](DatadogPipelineBuilder::install_batch) with an explicit
+//! async runtime (e.g. `opentelemetry::runtime::Tokio`) to have spans
+//! buffered and exported by a `BatchSpanProcessor` instead of
+//! [`install_simple`](DatadogPipelineBuilder::install_simple).
 //!
 //! ```toml
 //! [dependencies]
-//! opentelemetry = { version = "*", features = ["tokio"] }
+//! opentelemetry = { version = "*", features = ["rt-tokio"] }
 //! opentelemetry-datadog = "*"
 //! ```
 //!
-//! [`tokio`]: https://tokio.rs
-//! [`async-std`]: https://async.rs
-//!
 
 //! ## Bring your own http client
 //!
@@ -108,7 +119,7 @@
 //!                 .with_default_sampler(Sampler::AlwaysOn)
 //!                 .with_id_generator(IdGenerator::default())
 //!         )
-//!         .install()?;
+//!         .install_simple()?;
 //!
 //!     tracer.in_span("doing_work", |cx| {
 //!         // Traced app logic here...
@@ -120,17 +131,29 @@
 #![deny(missing_docs, unreachable_pub, missing_debug_implementations)]
 #![cfg_attr(test, deny(warnings))]
 
+mod correlation;
 mod intern;
 mod model;
+mod obfuscation;
+mod propagator;
+mod stats;
 
+pub use correlation::log_correlation_ids;
 pub use model::ApiVersion;
+pub use obfuscation::ObfuscationConfig;
+pub use propagator::DatadogPropagator;
 
 use async_trait::async_trait;
 use http::{Method, Request, Uri};
+use model::EncodeOptions;
+use obfuscation::Obfuscator;
+use opentelemetry::api::Key;
 use opentelemetry::exporter::trace;
 use opentelemetry::exporter::trace::{HttpClient, SpanData};
 use opentelemetry::{api::trace::TracerProvider, global, sdk};
+use stats::StatsAggregator;
 use std::error::Error;
+use std::sync::Mutex;
 
 /// Default Datadog collector endpoint
 const DEFAULT_AGENT_ENDPOINT: &str = "http://127.0.0.1:8126";
@@ -138,22 +161,88 @@ const DEFAULT_AGENT_ENDPOINT: &str = "http://127.0.0.1:8126";
 /// Default service name if no service is configured.
 const DEFAULT_SERVICE_NAME: &str = "OpenTelemetry";
 
+/// Default Datadog site used for agentless export when none is configured.
+const DEFAULT_SITE: &str = "datadoghq.com";
+
+/// Content type used when exporting directly to the Datadog trace intake.
+const INTAKE_CONTENT_TYPE: &str = "application/x-protobuf";
+
+/// HTTP header carrying the Datadog API key for agentless export.
+const DD_API_KEY_HEADER: &str = "DD-Api-Key";
+
+/// Default maximum number of spans buffered by the `BatchSpanProcessor`
+/// before older spans are dropped.
+const DEFAULT_MAX_QUEUE_SIZE: usize = 2048;
+
+/// Default maximum number of spans exported in a single batch.
+const DEFAULT_MAX_EXPORT_BATCH_SIZE: usize = 512;
+
+/// Default delay between scheduled batch exports.
+const DEFAULT_SCHEDULED_DELAY: std::time::Duration = std::time::Duration::from_millis(5000);
+
+/// Sizing knobs for [`DatadogPipelineBuilder::install_batch`].
+#[derive(Debug, Clone)]
+struct BatchConfig {
+    max_queue_size: usize,
+    max_export_batch_size: usize,
+    scheduled_delay: std::time::Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_queue_size: DEFAULT_MAX_QUEUE_SIZE,
+            max_export_batch_size: DEFAULT_MAX_EXPORT_BATCH_SIZE,
+            scheduled_delay: DEFAULT_SCHEDULED_DELAY,
+        }
+    }
+}
+
+/// Where a batch of spans should be sent.
+#[derive(Debug, Clone)]
+enum ExportTarget {
+    /// A local or sidecar `datadog-agent`, speaking the versioned agent API.
+    Agent(ApiVersion),
+    /// The public Datadog trace intake, authenticated with an API key.
+    Intake { api_key: String },
+}
+
 /// Datadog span exporter
 #[derive(Debug)]
 pub struct DatadogExporter<C> {
     client: C,
     request_url: Uri,
+    stats_url: Uri,
     service_name: String,
-    version: ApiVersion,
+    env: Option<String>,
+    version: Option<String>,
+    target: ExportTarget,
+    stats: Mutex<StatsAggregator>,
+    obfuscator: Option<Obfuscator>,
 }
 
 impl<C> DatadogExporter<C> {
-    fn new(service_name: String, request_url: Uri, version: ApiVersion, client: C) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        service_name: String,
+        env: Option<String>,
+        version: Option<String>,
+        request_url: Uri,
+        stats_url: Uri,
+        target: ExportTarget,
+        obfuscator: Option<Obfuscator>,
+        client: C,
+    ) -> Self {
         Self {
             client,
             request_url,
+            stats_url,
             service_name,
+            env,
             version,
+            target,
+            stats: Mutex::new(StatsAggregator::new()),
+            obfuscator,
         }
     }
 }
@@ -161,10 +250,16 @@ impl<C> DatadogExporter<C> {
 /// Builder for `ExporterConfig` struct.
 #[derive(Debug)]
 pub struct DatadogPipelineBuilder<C> {
-    service_name: String,
+    service_name: Option<String>,
     agent_endpoint: String,
     trace_config: Option<sdk::trace::Config>,
     version: ApiVersion,
+    api_key: Option<String>,
+    site: String,
+    obfuscation: Option<ObfuscationConfig>,
+    env: Option<String>,
+    service_version: Option<String>,
+    batch_config: Option<BatchConfig>,
     client: C,
 }
 
@@ -177,30 +272,134 @@ impl<C> DatadogPipelineBuilder<C> {
     /// Create a new DatadogPipelineBuilder with a particular client
     pub fn new(client: C) -> Self {
         Self {
-            service_name: DEFAULT_SERVICE_NAME.to_string(),
+            service_name: None,
             agent_endpoint: DEFAULT_AGENT_ENDPOINT.to_string(),
             trace_config: None,
             version: ApiVersion::Version05,
+            api_key: None,
+            site: DEFAULT_SITE.to_string(),
+            obfuscation: None,
+            env: None,
+            service_version: None,
+            batch_config: None,
             client,
         }
     }
 
-    /// Create `ExporterConfig` struct from current `ExporterConfigBuilder`
-    pub fn install(
+    /// Consume `self` into the configured [`DatadogExporter`] plus whatever
+    /// trace config is left to apply to the provider, resolving unified
+    /// service tagging from the trace config's `Resource` along the way.
+    /// Shared by [`install_simple`](Self::install_simple) and
+    /// [`install_batch`](Self::install_batch).
+    fn build_exporter(
         mut self,
+    ) -> Result<
+        (DatadogExporter<C>, Option<sdk::trace::Config>),
+        Box<dyn Error + Send + Sync + 'static>,
+    > {
+        // Unified service tagging: the SDK `Resource` (if any) supplies
+        // defaults for service name/env/version, with explicit builder
+        // overrides always taking precedence.
+        let resource = self
+            .trace_config
+            .as_ref()
+            .and_then(|config| config.resource.clone());
+        let resource_attr = |key: &'static str| {
+            resource
+                .as_ref()
+                .and_then(|resource| resource.get(Key::new(key)))
+                .map(|value| value.to_string())
+        };
+        let service_name = self
+            .service_name
+            .take()
+            .or_else(|| resource_attr("service.name"))
+            .unwrap_or_else(|| DEFAULT_SERVICE_NAME.to_string());
+        let env = self
+            .env
+            .take()
+            .or_else(|| resource_attr("deployment.environment"));
+        let version = self
+            .service_version
+            .take()
+            .or_else(|| resource_attr("service.version"));
+
+        let (request_url, stats_url, target) = match self.api_key.take() {
+            // Only switch to the agentless intake once an API key is actually
+            // present; otherwise fall back to the local agent endpoint.
+            Some(api_key) => (
+                format!("https://trace.agent.{}/api/v0.2/traces", self.site),
+                format!("https://trace.agent.{}/api/v0.2/stats", self.site),
+                ExportTarget::Intake { api_key },
+            ),
+            None => (
+                self.agent_endpoint.clone() + self.version.path(),
+                self.agent_endpoint.clone() + "/v0.6/stats",
+                ExportTarget::Agent(self.version),
+            ),
+        };
+        let exporter = DatadogExporter::new(
+            service_name,
+            env,
+            version,
+            request_url.parse()?,
+            stats_url.parse()?,
+            target,
+            self.obfuscation.take().map(Obfuscator::new),
+            self.client,
+        );
+        Ok((exporter, self.trace_config.take()))
+    }
+
+    /// Install the exporter as the global tracer provider, exporting each
+    /// batch of spans synchronously as it completes.
+    ///
+    /// Prefer [`install_batch`](Self::install_batch) for high-throughput
+    /// services, since this exports on every span batch rather than
+    /// buffering them.
+    pub fn install_simple(
+        self,
     ) -> Result<(sdk::trace::Tracer, Uninstall), Box<dyn Error + Send + Sync + 'static>>
     where
         C: HttpClient + std::fmt::Debug + Send + Sync + 'static,
     {
-        let endpoint = self.agent_endpoint + self.version.path();
-        let exporter = DatadogExporter::new(
-            self.service_name.clone(),
-            endpoint.parse()?,
-            self.version,
-            self.client,
-        );
+        let (exporter, trace_config) = self.build_exporter()?;
         let mut provider_builder = sdk::trace::TracerProvider::builder().with_exporter(exporter);
-        if let Some(config) = self.trace_config.take() {
+        if let Some(config) = trace_config {
+            provider_builder = provider_builder.with_config(config);
+        }
+        let provider = provider_builder.build();
+        let tracer = provider.get_tracer("opentelemetry-datadog", Some(env!("CARGO_PKG_VERSION")));
+        let provider_guard = global::set_tracer_provider(provider);
+        Ok((tracer, Uninstall(provider_guard)))
+    }
+
+    /// Install the exporter as the global tracer provider, batching spans
+    /// through a `BatchSpanProcessor` driven by the given async runtime
+    /// (e.g. `opentelemetry::runtime::Tokio`), rather than exporting
+    /// synchronously on every span completion.
+    ///
+    /// Batch sizing is controlled by
+    /// [`with_max_queue_size`](Self::with_max_queue_size),
+    /// [`with_max_export_batch_size`](Self::with_max_export_batch_size) and
+    /// [`with_scheduled_delay`](Self::with_scheduled_delay).
+    pub fn install_batch<R: sdk::trace::TraceRuntime>(
+        mut self,
+        runtime: R,
+    ) -> Result<(sdk::trace::Tracer, Uninstall), Box<dyn Error + Send + Sync + 'static>>
+    where
+        C: HttpClient + std::fmt::Debug + Send + Sync + 'static,
+    {
+        let batch_config = self.batch_config.take().unwrap_or_default();
+        let (exporter, trace_config) = self.build_exporter()?;
+        let batch_processor = sdk::trace::BatchSpanProcessor::builder(exporter, runtime)
+            .with_max_queue_size(batch_config.max_queue_size)
+            .with_max_export_batch_size(batch_config.max_export_batch_size)
+            .with_scheduled_delay(batch_config.scheduled_delay)
+            .build();
+        let mut provider_builder =
+            sdk::trace::TracerProvider::builder().with_span_processor(batch_processor);
+        if let Some(config) = trace_config {
             provider_builder = provider_builder.with_config(config);
         }
         let provider = provider_builder.build();
@@ -209,9 +408,42 @@ impl<C> DatadogPipelineBuilder<C> {
         Ok((tracer, Uninstall(provider_guard)))
     }
 
+    /// Install the exporter as the global tracer provider, exporting each
+    /// batch of spans synchronously as it completes.
+    #[deprecated(note = "use `install_simple` or `install_batch` instead")]
+    pub fn install(
+        self,
+    ) -> Result<(sdk::trace::Tracer, Uninstall), Box<dyn Error + Send + Sync + 'static>>
+    where
+        C: HttpClient + std::fmt::Debug + Send + Sync + 'static,
+    {
+        self.install_simple()
+    }
+
     /// Assign the service name under which to group traces
+    ///
+    /// Overrides the `service.name` resource attribute, if any, on the
+    /// config passed to [`with_trace_config`](Self::with_trace_config).
     pub fn with_service_name(mut self, name: impl Into<String>) -> Self {
-        self.service_name = name.into();
+        self.service_name = Some(name.into());
+        self
+    }
+
+    /// Assign the environment (Datadog's `env` tag) traces from this
+    /// pipeline are tagged with.
+    ///
+    /// Overrides the `deployment.environment` resource attribute, if any.
+    pub fn with_env(mut self, env: impl Into<String>) -> Self {
+        self.env = Some(env.into());
+        self
+    }
+
+    /// Assign the service version (Datadog's `version` tag) traces from this
+    /// pipeline are tagged with.
+    ///
+    /// Overrides the `service.version` resource attribute, if any.
+    pub fn with_service_version(mut self, version: impl Into<String>) -> Self {
+        self.service_version = Some(version.into());
         self
     }
 
@@ -232,23 +464,123 @@ impl<C> DatadogPipelineBuilder<C> {
         self.version = version;
         self
     }
+
+    /// Set the Datadog API key and switch to agentless export, posting spans
+    /// directly to the Datadog trace intake instead of a local `datadog-agent`.
+    ///
+    /// Has no effect unless an API key is present at
+    /// [`install_simple`](Self::install_simple)/[`install_batch`](Self::install_batch)
+    /// time; without one, the exporter falls back to the agent endpoint set by
+    /// [`with_agent_endpoint`](Self::with_agent_endpoint).
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// Set the Datadog site to use for agentless export, e.g. `datadoghq.eu`.
+    ///
+    /// Defaults to `datadoghq.com`. Only takes effect when
+    /// [`with_api_key`](Self::with_api_key) is also used.
+    pub fn with_site(mut self, site: impl Into<String>) -> Self {
+        self.site = site.into();
+        self
+    }
+
+    /// Enable resource-name obfuscation for `sql`/`db`, `redis` and
+    /// `memcached` spans, stripping literal values before export.
+    ///
+    /// Disabled by default, since it changes what shows up as the span
+    /// resource in Datadog.
+    pub fn with_obfuscation(mut self, config: ObfuscationConfig) -> Self {
+        self.obfuscation = Some(config);
+        self
+    }
+
+    /// Set the maximum number of spans the `BatchSpanProcessor` buffers
+    /// before it starts dropping older ones. Only used by
+    /// [`install_batch`](Self::install_batch).
+    pub fn with_max_queue_size(mut self, size: usize) -> Self {
+        self.batch_config
+            .get_or_insert_with(BatchConfig::default)
+            .max_queue_size = size;
+        self
+    }
+
+    /// Set the maximum number of spans exported in a single batch. Only
+    /// used by [`install_batch`](Self::install_batch).
+    pub fn with_max_export_batch_size(mut self, size: usize) -> Self {
+        self.batch_config
+            .get_or_insert_with(BatchConfig::default)
+            .max_export_batch_size = size;
+        self
+    }
+
+    /// Set the delay between scheduled batch exports. Only used by
+    /// [`install_batch`](Self::install_batch).
+    pub fn with_scheduled_delay(mut self, delay: std::time::Duration) -> Self {
+        self.batch_config
+            .get_or_insert_with(BatchConfig::default)
+            .scheduled_delay = delay;
+        self
+    }
 }
 
 #[async_trait]
 impl<C: HttpClient + std::fmt::Debug + Send + Sync> trace::SpanExporter for DatadogExporter<C> {
-    /// Export spans to datadog-agent
+    /// Export spans to datadog-agent, or directly to the Datadog trace intake
+    /// when configured for agentless export.
     async fn export(&self, batch: Vec<SpanData>) -> trace::ExportResult {
-        let data = match self.version.encode(&self.service_name, batch) {
-            Ok(data) => data,
-            Err(_) => return trace::ExportResult::FailedNotRetryable,
+        if let Ok(mut stats) = self.stats.lock() {
+            stats.add_spans(&self.service_name, &batch, self.obfuscator.as_ref());
+        }
+
+        let opts = EncodeOptions {
+            obfuscator: self.obfuscator.as_ref(),
+            env: self.env.as_deref(),
+            version: self.version.as_deref(),
+        };
+        let (data, content_type) = match &self.target {
+            ExportTarget::Agent(api_version) => {
+                match api_version.encode(&self.service_name, batch, &opts) {
+                    Ok(data) => (data, api_version.content_type()),
+                    Err(_) => return trace::ExportResult::FailedNotRetryable,
+                }
+            }
+            ExportTarget::Intake { .. } => {
+                match model::encode_v02_protobuf(&self.service_name, batch, &opts) {
+                    Ok(data) => (data, INTAKE_CONTENT_TYPE),
+                    Err(_) => return trace::ExportResult::FailedNotRetryable,
+                }
+            }
         };
 
-        let req = match Request::builder()
+        let result = self
+            .send(self.request_url.clone(), content_type, data)
+            .await;
+        // Stats are flushed as a best-effort, separate request whenever a
+        // bucket's window has closed; a failure here shouldn't fail the span
+        // export itself. Most calls are a no-op, since buckets only close
+        // every 10s regardless of how often spans are exported.
+        let _ = self.flush_stats().await;
+        result
+    }
+}
+
+impl<C: HttpClient + std::fmt::Debug + Send + Sync> DatadogExporter<C> {
+    async fn send(
+        &self,
+        url: Uri,
+        content_type: &'static str,
+        body: Vec<u8>,
+    ) -> trace::ExportResult {
+        let mut builder = Request::builder()
             .method(Method::POST)
-            .uri(self.request_url.clone())
-            .header(http::header::CONTENT_TYPE, self.version.content_type())
-            .body(data)
-        {
+            .uri(url)
+            .header(http::header::CONTENT_TYPE, content_type);
+        if let ExportTarget::Intake { api_key } = &self.target {
+            builder = builder.header(DD_API_KEY_HEADER, api_key.as_str());
+        }
+        let req = match builder.body(body) {
             Ok(req) => req,
             _ => return trace::ExportResult::FailedNotRetryable,
         };
@@ -257,8 +589,107 @@ impl<C: HttpClient + std::fmt::Debug + Send + Sync> trace::SpanExporter for Data
             .await
             .unwrap_or(trace::ExportResult::FailedNotRetryable)
     }
+
+    async fn flush_stats(&self) -> trace::ExportResult {
+        let now_ns = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let payload = match self.stats.lock() {
+            Ok(mut stats) => match stats.flush_closed(now_ns) {
+                Some(payload) => payload,
+                None => return trace::ExportResult::Success,
+            },
+            Err(_) => return trace::ExportResult::Success,
+        };
+        // `StatsAggregator::flush_closed` always writes length-prefixed
+        // protobuf, for both the agent and intake targets, so the content
+        // type is the same regardless of which one we're sending to.
+        self.send(self.stats_url.clone(), INTAKE_CONTENT_TYPE, payload)
+            .await
+    }
 }
 
 /// Uninstalls the Datadog pipeline on drop
 #[derive(Debug)]
 pub struct Uninstall(global::TracerProviderGuard);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::api::KeyValue;
+
+    fn builder() -> DatadogPipelineBuilder<()> {
+        DatadogPipelineBuilder::new(())
+    }
+
+    #[test]
+    fn api_key_present_selects_agentless_intake_target() {
+        let (exporter, _) = builder()
+            .with_api_key("dd-key")
+            .with_site("datadoghq.eu")
+            .build_exporter()
+            .unwrap();
+
+        match exporter.target {
+            ExportTarget::Intake { api_key } => assert_eq!(api_key, "dd-key"),
+            ExportTarget::Agent(_) => panic!("expected an agentless intake target"),
+        }
+        assert!(exporter
+            .request_url
+            .to_string()
+            .starts_with("https://trace.agent.datadoghq.eu"));
+    }
+
+    #[test]
+    fn no_api_key_falls_back_to_agent_target() {
+        let (exporter, _) = builder()
+            .with_agent_endpoint("http://localhost:8126")
+            .build_exporter()
+            .unwrap();
+
+        match exporter.target {
+            ExportTarget::Agent(version) => assert_eq!(version, ApiVersion::Version05),
+            ExportTarget::Intake { .. } => panic!("expected the local agent target"),
+        }
+        assert_eq!(
+            exporter.request_url.to_string(),
+            "http://localhost:8126/v0.5/traces"
+        );
+    }
+
+    fn resource_with_unified_tags() -> sdk::Resource {
+        sdk::Resource::new(vec![
+            KeyValue::new("service.name", "resource-service"),
+            KeyValue::new("deployment.environment", "resource-env"),
+            KeyValue::new("service.version", "resource-version"),
+        ])
+    }
+
+    #[test]
+    fn builder_overrides_win_over_resource_attributes() {
+        let (exporter, _) = builder()
+            .with_service_name("override-service")
+            .with_env("override-env")
+            .with_service_version("override-version")
+            .with_trace_config(sdk::trace::config().with_resource(resource_with_unified_tags()))
+            .build_exporter()
+            .unwrap();
+
+        assert_eq!(exporter.service_name, "override-service");
+        assert_eq!(exporter.env.as_deref(), Some("override-env"));
+        assert_eq!(exporter.version.as_deref(), Some("override-version"));
+    }
+
+    #[test]
+    fn resource_attributes_are_used_when_no_override_is_set() {
+        let (exporter, _) = builder()
+            .with_trace_config(sdk::trace::config().with_resource(resource_with_unified_tags()))
+            .build_exporter()
+            .unwrap();
+
+        assert_eq!(exporter.service_name, "resource-service");
+        assert_eq!(exporter.env.as_deref(), Some("resource-env"));
+        assert_eq!(exporter.version.as_deref(), Some("resource-version"));
+    }
+}
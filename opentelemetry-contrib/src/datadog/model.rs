@@ -0,0 +1,232 @@
+use super::obfuscation::Obfuscator;
+use opentelemetry::exporter::trace::SpanData;
+use std::error::Error;
+
+pub(crate) fn span_type(span: &SpanData) -> String {
+    span.attributes
+        .iter()
+        .find(|kv| kv.key.as_str() == "span.type")
+        .map(|kv| kv.value.to_string())
+        .unwrap_or_default()
+}
+
+pub(crate) fn resource_name(span: &SpanData, obfuscator: Option<&Obfuscator>) -> String {
+    let span_type = span_type(span);
+    obfuscator
+        .and_then(|o| o.obfuscate(&span_type, span.name.as_ref()))
+        .unwrap_or_else(|| span.name.to_string())
+}
+
+/// Per-export settings that apply uniformly to every span in the batch,
+/// bundled together since the list keeps growing with each new encoding
+/// feature.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct EncodeOptions<'a> {
+    pub(crate) obfuscator: Option<&'a Obfuscator>,
+    /// Unified service tagging `env`, read from the `deployment.environment`
+    /// resource attribute unless overridden on the builder.
+    pub(crate) env: Option<&'a str>,
+    /// Unified service tagging `version`, read from the `service.version`
+    /// resource attribute unless overridden on the builder.
+    pub(crate) version: Option<&'a str>,
+}
+
+/// Version of the Datadog agent trace ingestion API in use.
+///
+/// Each version corresponds to a different wire format and agent endpoint.
+/// See the [Datadog agent docs](https://docs.datadoghq.com/agent/) for details.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ApiVersion {
+    /// Version 0.3
+    Version03,
+    /// Version 0.4
+    Version04,
+    /// Version 0.5
+    Version05,
+}
+
+impl ApiVersion {
+    pub(crate) fn path(self) -> &'static str {
+        match self {
+            ApiVersion::Version03 => "/v0.3/traces",
+            ApiVersion::Version04 => "/v0.4/traces",
+            ApiVersion::Version05 => "/v0.5/traces",
+        }
+    }
+
+    pub(crate) fn content_type(self) -> &'static str {
+        "application/msgpack"
+    }
+
+    /// Encode a batch of spans for the given agent API version.
+    ///
+    /// The wire format is msgpack, with the same shape for every version;
+    /// all versions group spans into a single trace list for the
+    /// duration of the batch, which is an acceptable simplification since
+    /// the agent only uses the grouping for display purposes.
+    pub(crate) fn encode(
+        self,
+        service_name: &str,
+        spans: Vec<SpanData>,
+        opts: &EncodeOptions<'_>,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync + 'static>> {
+        encode_msgpack(service_name, spans, opts)
+    }
+}
+
+/// Encode a batch of spans as a `v0.2` `TracePayload` protobuf message, as
+/// expected by the public Datadog trace intake used for agentless export.
+pub(crate) fn encode_v02_protobuf(
+    service_name: &str,
+    spans: Vec<SpanData>,
+    opts: &EncodeOptions<'_>,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync + 'static>> {
+    let mut trace_chunk = Vec::new();
+    for span in spans {
+        let mut span_buf = Vec::new();
+        write_protobuf_string_field(&mut span_buf, 1, service_name);
+        write_protobuf_string_field(&mut span_buf, 2, "opentelemetry");
+        write_protobuf_string_field(&mut span_buf, 3, &resource_name(&span, opts.obfuscator));
+        write_protobuf_varint_field(
+            &mut span_buf,
+            4,
+            span.span_context.trace_id().to_u128() as u64,
+        );
+        write_protobuf_varint_field(&mut span_buf, 5, span.span_context.span_id().to_u64());
+        write_protobuf_varint_field(
+            &mut span_buf,
+            8,
+            span.start_time
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64,
+        );
+        write_protobuf_varint_field(
+            &mut span_buf,
+            9,
+            span.end_time
+                .duration_since(span.start_time)
+                .unwrap_or_default()
+                .as_nanos() as u64,
+        );
+        if let Some(env) = opts.env {
+            write_protobuf_string_field(&mut span_buf, 10, env);
+        }
+        if let Some(version) = opts.version {
+            write_protobuf_string_field(&mut span_buf, 11, version);
+        }
+        // field 1 (repeated Span spans) on the enclosing TraceChunk
+        write_protobuf_tag(&mut trace_chunk, 1, 2);
+        write_protobuf_varint(&mut trace_chunk, span_buf.len() as u64);
+        trace_chunk.extend_from_slice(&span_buf);
+    }
+
+    let mut payload = Vec::new();
+    write_protobuf_string_field(&mut payload, 1, "opentelemetry-datadog");
+    // field 2 (repeated TraceChunk chunks)
+    write_protobuf_tag(&mut payload, 2, 2);
+    write_protobuf_varint(&mut payload, trace_chunk.len() as u64);
+    payload.extend_from_slice(&trace_chunk);
+
+    Ok(payload)
+}
+
+fn write_protobuf_tag(buf: &mut Vec<u8>, field: u32, wire_type: u8) {
+    write_protobuf_varint(buf, ((field << 3) | wire_type as u32) as u64);
+}
+
+fn write_protobuf_varint(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            break;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn write_protobuf_varint_field(buf: &mut Vec<u8>, field: u32, value: u64) {
+    write_protobuf_tag(buf, field, 0);
+    write_protobuf_varint(buf, value);
+}
+
+fn write_protobuf_string_field(buf: &mut Vec<u8>, field: u32, value: &str) {
+    write_protobuf_tag(buf, field, 2);
+    write_protobuf_varint(buf, value.len() as u64);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+fn encode_msgpack(
+    service_name: &str,
+    spans: Vec<SpanData>,
+    opts: &EncodeOptions<'_>,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync + 'static>> {
+    let mut encoded = Vec::new();
+    rmp::encode::write_array_len(&mut encoded, 1)?;
+    rmp::encode::write_array_len(&mut encoded, spans.len() as u32)?;
+    for span in spans {
+        encode_span(&mut encoded, service_name, span, opts)?;
+    }
+    Ok(encoded)
+}
+
+fn encode_span(
+    buf: &mut Vec<u8>,
+    service_name: &str,
+    span: SpanData,
+    opts: &EncodeOptions<'_>,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    rmp::encode::write_map_len(buf, 9)?;
+
+    rmp::encode::write_str(buf, "service")?;
+    rmp::encode::write_str(buf, service_name)?;
+
+    rmp::encode::write_str(buf, "name")?;
+    rmp::encode::write_str(buf, "opentelemetry")?;
+
+    rmp::encode::write_str(buf, "resource")?;
+    rmp::encode::write_str(buf, &resource_name(&span, opts.obfuscator))?;
+
+    rmp::encode::write_str(buf, "trace_id")?;
+    rmp::encode::write_u64(buf, span.span_context.trace_id().to_u128() as u64)?;
+
+    rmp::encode::write_str(buf, "span_id")?;
+    rmp::encode::write_u64(buf, span.span_context.span_id().to_u64())?;
+
+    rmp::encode::write_str(buf, "start")?;
+    rmp::encode::write_sint(
+        buf,
+        span.start_time
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as i64,
+    )?;
+
+    rmp::encode::write_str(buf, "duration")?;
+    rmp::encode::write_sint(
+        buf,
+        span.end_time
+            .duration_since(span.start_time)
+            .unwrap_or_default()
+            .as_nanos() as i64,
+    )?;
+
+    rmp::encode::write_str(buf, "error")?;
+    rmp::encode::write_sint(buf, 0)?;
+
+    rmp::encode::write_str(buf, "meta")?;
+    let meta: Vec<(&str, &str)> = [("env", opts.env), ("version", opts.version)]
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|value| (key, value)))
+        .collect();
+    rmp::encode::write_map_len(buf, meta.len() as u32)?;
+    for (key, value) in meta {
+        rmp::encode::write_str(buf, key)?;
+        rmp::encode::write_str(buf, value)?;
+    }
+
+    Ok(())
+}
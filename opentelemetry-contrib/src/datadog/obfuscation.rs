@@ -0,0 +1,248 @@
+//! Resource-name obfuscation for spans carrying raw query text.
+//!
+//! Spans describing a SQL/cache operation often put the literal query in
+//! their resource name, which leaks PII and blows up cardinality in the
+//! Datadog UI (every distinct `WHERE id = 123` becomes its own resource).
+//! [`Obfuscator`] rewrites those resource names per `span.type` before
+//! export, keeping the shape of the operation while dropping literal values.
+
+/// Configuration for resource-name obfuscation.
+///
+/// Each field enables obfuscation for the matching `span.type`; disabled
+/// types are left untouched. Construct with [`ObfuscationConfig::default`]
+/// to enable all known types.
+#[derive(Debug, Clone)]
+pub struct ObfuscationConfig {
+    /// Obfuscate `sql`/`db` span resources.
+    pub obfuscate_sql: bool,
+    /// Obfuscate `redis` span resources.
+    pub obfuscate_redis: bool,
+    /// Obfuscate `memcached` span resources.
+    pub obfuscate_memcached: bool,
+}
+
+impl Default for ObfuscationConfig {
+    fn default() -> Self {
+        Self {
+            obfuscate_sql: true,
+            obfuscate_redis: true,
+            obfuscate_memcached: true,
+        }
+    }
+}
+
+/// Rewrites span resource names to strip literal values, based on the
+/// span's `span.type`.
+///
+/// Allocation-light and deterministic, since it runs on every exported
+/// span: SQL is rewritten in one pass replacing literals and a second pass
+/// collapsing `IN` lists, with no intermediate tokenization structures.
+#[derive(Debug, Clone)]
+pub(crate) struct Obfuscator {
+    config: ObfuscationConfig,
+}
+
+impl Obfuscator {
+    pub(crate) fn new(config: ObfuscationConfig) -> Self {
+        Self { config }
+    }
+
+    /// Obfuscate `resource` according to `span_type`, if obfuscation for
+    /// that type is enabled. Returns `None` when no rewrite applies, so
+    /// callers can fall back to the original resource without a copy.
+    pub(crate) fn obfuscate(&self, span_type: &str, resource: &str) -> Option<String> {
+        match span_type {
+            "sql" | "db" if self.config.obfuscate_sql => Some(obfuscate_sql(resource)),
+            "redis" if self.config.obfuscate_redis => Some(obfuscate_redis(resource)),
+            "memcached" if self.config.obfuscate_memcached => Some(obfuscate_memcached(resource)),
+            _ => None,
+        }
+    }
+}
+
+/// Replace numeric, quoted-string and hex-blob literals with `?`, then
+/// collapse repeated `IN (?, ?, ...)` lists into a single `IN (?)`.
+///
+/// A digit only starts a numeric literal when it isn't already part of an
+/// identifier (e.g. the `1` in `t1` or the `2` in `oauth2_token`), so
+/// obfuscation doesn't corrupt table/column names that happen to contain
+/// digits.
+fn obfuscate_sql(query: &str) -> String {
+    let mut out = String::with_capacity(query.len());
+    let mut chars = query.char_indices().peekable();
+    // Tracked alongside `out` instead of re-derived from it on every digit
+    // (`Chars::last()` has no override and walks the whole string), so this
+    // stays O(n) even on resources with many numeric literals.
+    let mut last_pushed: Option<char> = None;
+
+    while let Some((_, c)) = chars.next() {
+        if c == '\'' || c == '"' {
+            out.push('?');
+            last_pushed = Some('?');
+            let quote = c;
+            for (_, next) in chars.by_ref() {
+                if next == quote {
+                    break;
+                }
+            }
+        } else if c.is_ascii_digit() && !last_pushed.map_or(false, is_ident_char) {
+            out.push('?');
+            last_pushed = Some('?');
+            while matches!(chars.peek(), Some((_, next)) if next.is_ascii_hexdigit() || *next == 'x' || *next == '.')
+            {
+                chars.next();
+            }
+        } else {
+            out.push(c);
+            last_pushed = Some(c);
+        }
+    }
+
+    collapse_in_lists(&out)
+}
+
+/// Whether `c` can appear in a SQL identifier, used to tell a genuine
+/// literal boundary from a digit or `IN (` that's actually part of a larger
+/// identifier (`t1`, `oauth2_token`, `JOIN (`, `DOMAIN (`).
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Collapse a `IN (?, ?, ?)`-shaped list (after literal replacement) down to
+/// `IN (?)`, so that `IN` clauses of varying arity don't fragment the
+/// resource by cardinality.
+fn collapse_in_lists(query: &str) -> String {
+    const MARKER: &str = "IN (";
+    let mut out = String::with_capacity(query.len());
+    let mut rest = query;
+
+    while let Some(start) = find_case_insensitive(rest, MARKER) {
+        let (head, tail) = rest.split_at(start + MARKER.len());
+        // Require the match to sit at a word boundary, not preceded by an
+        // identifier char, so `JOIN (`/`DOMAIN (`/`MAIN (` aren't mistaken
+        // for an `IN (` list.
+        let preceded_by_ident = start > 0 && is_ident_char(rest.as_bytes()[start - 1] as char);
+        if preceded_by_ident {
+            out.push_str(head);
+            rest = tail;
+            continue;
+        }
+        out.push_str(&rest[..start]);
+        out.push_str(&head[head.len() - MARKER.len()..]);
+        if let Some(close) = tail.find(')') {
+            let list = &tail[..close];
+            if list.split(',').all(|item| item.trim() == "?") && !list.trim().is_empty() {
+                out.push('?');
+                out.push(')');
+            } else {
+                out.push_str(&tail[..=close]);
+            }
+            rest = &tail[close + 1..];
+        } else {
+            out.push_str(tail);
+            rest = "";
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn find_case_insensitive(haystack: &str, needle: &str) -> Option<usize> {
+    let haystack_upper = haystack.to_ascii_uppercase();
+    haystack_upper.find(&needle.to_ascii_uppercase())
+}
+
+/// Keep the Redis command verb and drop argument values, e.g. `GET foo` ->
+/// `GET`.
+fn obfuscate_redis(command: &str) -> String {
+    command
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Keep the memcached command and key shape, dropping values, e.g.
+/// `set foo 0 0 3` -> `set foo`.
+fn obfuscate_memcached(command: &str) -> String {
+    let mut parts = command.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(verb), Some(key)) => format!("{} {}", verb, key),
+        (Some(verb), None) => verb.to_string(),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sql_literals_are_replaced() {
+        assert_eq!(
+            obfuscate_sql("SELECT * FROM users WHERE id = 123"),
+            "SELECT * FROM users WHERE id = ?"
+        );
+        assert_eq!(
+            obfuscate_sql("SELECT * FROM users WHERE name = 'bob'"),
+            "SELECT * FROM users WHERE name = ?"
+        );
+    }
+
+    #[test]
+    fn identifiers_containing_digits_are_left_untouched() {
+        assert_eq!(obfuscate_sql("SELECT * FROM t1"), "SELECT * FROM t1");
+        assert_eq!(
+            obfuscate_sql("SELECT oauth2_token FROM users"),
+            "SELECT oauth2_token FROM users"
+        );
+    }
+
+    #[test]
+    fn in_lists_collapse_to_a_single_placeholder() {
+        assert_eq!(
+            obfuscate_sql("SELECT * FROM t WHERE id IN (1, 2, 3)"),
+            "SELECT * FROM t WHERE id IN (?)"
+        );
+    }
+
+    #[test]
+    fn words_ending_in_in_are_not_mistaken_for_in_lists() {
+        assert_eq!(
+            obfuscate_sql("SELECT * FROM a JOIN (SELECT 1) b ON true"),
+            "SELECT * FROM a JOIN (SELECT ?) b ON true"
+        );
+        assert_eq!(
+            obfuscate_sql("SELECT * FROM t WHERE DOMAIN (1)"),
+            "SELECT * FROM t WHERE DOMAIN (?)"
+        );
+    }
+
+    #[test]
+    fn redis_keeps_only_the_command_verb() {
+        assert_eq!(obfuscate_redis("GET foo"), "GET");
+        assert_eq!(obfuscate_redis("SET foo bar"), "SET");
+    }
+
+    #[test]
+    fn memcached_keeps_verb_and_key() {
+        assert_eq!(obfuscate_memcached("set foo 0 0 3"), "set foo");
+        assert_eq!(obfuscate_memcached("get foo"), "get foo");
+    }
+
+    #[test]
+    fn obfuscator_respects_config_flags() {
+        let config = ObfuscationConfig {
+            obfuscate_sql: false,
+            obfuscate_redis: true,
+            obfuscate_memcached: true,
+        };
+        let obfuscator = Obfuscator::new(config);
+
+        assert_eq!(obfuscator.obfuscate("sql", "SELECT 1"), None);
+        assert_eq!(
+            obfuscator.obfuscate("redis", "GET foo"),
+            Some("GET".to_string())
+        );
+    }
+}
@@ -0,0 +1,243 @@
+//! Propagator for the Datadog distributed tracing header format.
+//!
+//! See the [Datadog tracing headers docs](https://docs.datadoghq.com/tracing/guide/distributed_tracing/)
+//! for the header semantics this module implements.
+
+use opentelemetry::api::{
+    Context, Extractor, FieldIter, Injector, SpanContext, TextMapPropagator, TraceContextExt,
+    TraceFlags, TraceId, TraceState,
+};
+
+const DATADOG_TRACE_ID_HEADER: &str = "x-datadog-trace-id";
+const DATADOG_PARENT_ID_HEADER: &str = "x-datadog-parent-id";
+const DATADOG_SAMPLING_PRIORITY_HEADER: &str = "x-datadog-sampling-priority";
+const DATADOG_ORIGIN_HEADER: &str = "x-datadog-origin";
+
+/// The `x-datadog-*` headers this propagator reads and writes, as reported
+/// by [`TextMapPropagator::fields`].
+static DATADOG_HEADER_FIELDS: [&str; 4] = [
+    DATADOG_TRACE_ID_HEADER,
+    DATADOG_PARENT_ID_HEADER,
+    DATADOG_SAMPLING_PRIORITY_HEADER,
+    DATADOG_ORIGIN_HEADER,
+];
+
+/// `TraceState` key used to round-trip the `x-datadog-origin` header, which
+/// has no equivalent OTel field of its own.
+const DATADOG_ORIGIN_TRACE_STATE_KEY: &str = "dd_origin";
+
+/// Sampling priority as encoded in the `x-datadog-sampling-priority` header.
+///
+/// `UserReject`/`AutoReject` mean the trace should be dropped, while
+/// `AutoKeep`/`UserKeep` mean it should be kept; see the Datadog docs linked
+/// above for the distinction between the automatic and user-set variants.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum SamplingPriority {
+    UserReject = -1,
+    AutoReject = 0,
+    AutoKeep = 1,
+    UserKeep = 2,
+}
+
+/// Propagates Datadog's `x-datadog-*` distributed tracing headers.
+///
+/// OTel `TraceId`s are 128 bits while Datadog trace ids are 64 bits, so on
+/// inject only the low 64 bits of the trace id are sent, and on extract the
+/// 64-bit value is zero-extended back into a 128-bit `TraceId`.
+#[derive(Debug, Default)]
+pub struct DatadogPropagator {
+    _private: (),
+}
+
+impl DatadogPropagator {
+    /// Create a new `DatadogPropagator`.
+    pub fn new() -> Self {
+        DatadogPropagator::default()
+    }
+
+    fn extract_trace_id(&self, trace_id: &str) -> Option<TraceId> {
+        trace_id
+            .parse::<u64>()
+            .ok()
+            .map(|id| TraceId::from_u128(id as u128))
+    }
+
+    fn extract_span_id(&self, span_id: &str) -> Option<u64> {
+        span_id.parse::<u64>().ok()
+    }
+
+    fn extract_sampling_priority(&self, priority: &str) -> Option<SamplingPriority> {
+        match priority {
+            "-1" => Some(SamplingPriority::UserReject),
+            "0" => Some(SamplingPriority::AutoReject),
+            "1" => Some(SamplingPriority::AutoKeep),
+            "2" => Some(SamplingPriority::UserKeep),
+            _ => None,
+        }
+    }
+
+    fn extract_span_context(&self, extractor: &dyn Extractor) -> Option<SpanContext> {
+        let trace_id = self.extract_trace_id(extractor.get(DATADOG_TRACE_ID_HEADER)?)?;
+        let span_id = self.extract_span_id(extractor.get(DATADOG_PARENT_ID_HEADER)?)?;
+        let sampled = extractor
+            .get(DATADOG_SAMPLING_PRIORITY_HEADER)
+            .and_then(|priority| self.extract_sampling_priority(priority))
+            .map(|priority| priority as i8 >= SamplingPriority::AutoKeep as i8)
+            .unwrap_or(false);
+        let trace_flags = if sampled {
+            TraceFlags::SAMPLED
+        } else {
+            TraceFlags::default()
+        };
+
+        let trace_state = extractor
+            .get(DATADOG_ORIGIN_HEADER)
+            .and_then(|origin| {
+                TraceState::default()
+                    .insert(
+                        DATADOG_ORIGIN_TRACE_STATE_KEY.to_string(),
+                        origin.to_string(),
+                    )
+                    .ok()
+            })
+            .unwrap_or_default();
+
+        Some(SpanContext::new(
+            trace_id,
+            span_id.into(),
+            trace_flags,
+            true,
+            trace_state,
+        ))
+    }
+}
+
+impl TextMapPropagator for DatadogPropagator {
+    fn inject_context(&self, cx: &Context, injector: &mut dyn Injector) {
+        let span_context = cx.span().span_context();
+        if !span_context.is_valid() {
+            return;
+        }
+
+        let trace_id = span_context.trace_id().to_u128() as u64;
+        injector.set(DATADOG_TRACE_ID_HEADER, trace_id.to_string());
+        injector.set(
+            DATADOG_PARENT_ID_HEADER,
+            span_context.span_id().to_u64().to_string(),
+        );
+
+        let priority = if span_context.is_sampled() {
+            SamplingPriority::AutoKeep
+        } else {
+            SamplingPriority::AutoReject
+        };
+        injector.set(
+            DATADOG_SAMPLING_PRIORITY_HEADER,
+            (priority as i8).to_string(),
+        );
+
+        if let Some(origin) = span_context
+            .trace_state()
+            .get(DATADOG_ORIGIN_TRACE_STATE_KEY)
+        {
+            injector.set(DATADOG_ORIGIN_HEADER, origin.to_string());
+        }
+    }
+
+    fn extract_with_context(&self, cx: &Context, extractor: &dyn Extractor) -> Context {
+        let span_context = self.extract_span_context(extractor).unwrap_or_default();
+        cx.with_remote_span_context(span_context)
+    }
+
+    fn fields(&self) -> FieldIter<'_> {
+        FieldIter::new(&DATADOG_HEADER_FIELDS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::api::SpanId;
+    use std::collections::HashMap;
+
+    #[test]
+    fn fields_lists_all_datadog_headers() {
+        let propagator = DatadogPropagator::new();
+        let fields: Vec<&str> = propagator.fields().collect();
+        assert_eq!(
+            fields,
+            vec![
+                DATADOG_TRACE_ID_HEADER,
+                DATADOG_PARENT_ID_HEADER,
+                DATADOG_SAMPLING_PRIORITY_HEADER,
+                DATADOG_ORIGIN_HEADER,
+            ]
+        );
+    }
+
+    #[test]
+    fn inject_extract_round_trip() {
+        let propagator = DatadogPropagator::new();
+        let mut carrier = HashMap::new();
+        carrier.insert(DATADOG_TRACE_ID_HEADER.to_string(), "12345".to_string());
+        carrier.insert(DATADOG_PARENT_ID_HEADER.to_string(), "67".to_string());
+        carrier.insert(
+            DATADOG_SAMPLING_PRIORITY_HEADER.to_string(),
+            "1".to_string(),
+        );
+
+        let cx = propagator.extract_with_context(&Context::new(), &carrier);
+
+        let mut injected = HashMap::new();
+        propagator.inject_context(&cx, &mut injected);
+
+        assert_eq!(
+            injected.get(DATADOG_TRACE_ID_HEADER).map(String::as_str),
+            Some("12345")
+        );
+        assert_eq!(
+            injected.get(DATADOG_PARENT_ID_HEADER).map(String::as_str),
+            Some("67")
+        );
+        assert_eq!(
+            injected
+                .get(DATADOG_SAMPLING_PRIORITY_HEADER)
+                .map(String::as_str),
+            Some("1")
+        );
+    }
+
+    #[test]
+    fn trace_id_truncates_to_low_64_bits_on_inject() {
+        let propagator = DatadogPropagator::new();
+        let trace_id = TraceId::from_u128((0xdead_beef_u128 << 64) | 42);
+        let span_context = SpanContext::new(
+            trace_id,
+            SpanId::from_u64(7),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        );
+        let cx = Context::new().with_remote_span_context(span_context);
+
+        let mut injected = HashMap::new();
+        propagator.inject_context(&cx, &mut injected);
+
+        assert_eq!(
+            injected.get(DATADOG_TRACE_ID_HEADER).map(String::as_str),
+            Some("42")
+        );
+    }
+
+    #[test]
+    fn trace_id_zero_extends_on_extract() {
+        let propagator = DatadogPropagator::new();
+        let mut carrier = HashMap::new();
+        carrier.insert(DATADOG_TRACE_ID_HEADER.to_string(), "42".to_string());
+        carrier.insert(DATADOG_PARENT_ID_HEADER.to_string(), "7".to_string());
+
+        let cx = propagator.extract_with_context(&Context::new(), &carrier);
+
+        assert_eq!(cx.span().span_context().trace_id().to_u128(), 42);
+    }
+}
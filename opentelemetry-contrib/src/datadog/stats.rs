@@ -0,0 +1,365 @@
+//! APM trace statistics (hits, errors, latency distributions).
+//!
+//! Datadog's APM UI computes service-level metrics (request rate, error
+//! rate, latency percentiles) from these aggregates rather than from raw
+//! spans, which keeps them accurate even when the tracer is client-side
+//! sampling. See the [Datadog stats
+//! docs](https://docs.datadoghq.com/tracing/guide/trace_ingestion_volume_control/)
+//! for the intake this feeds.
+
+use super::model;
+use super::obfuscation::Obfuscator;
+use opentelemetry::exporter::trace::SpanData;
+use std::collections::HashMap;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Width of the time buckets spans are grouped into. 10s matches the
+/// granularity Datadog's stats intake expects.
+const BUCKET_DURATION: Duration = Duration::from_secs(10);
+
+/// Relative accuracy of the latency distributions, i.e. the maximum relative
+/// error between a reported quantile and the true quantile.
+const SKETCH_RELATIVE_ACCURACY: f64 = 0.01;
+
+/// A relative-error histogram, as used by Datadog's stats intake to report
+/// latency distributions without storing every individual duration.
+///
+/// For a configured relative accuracy `ε`, values are bucketed on a
+/// logarithmic scale with base `γ = (1+ε)/(1-ε)`: a positive value `v` falls
+/// into bucket `⌈log_γ(v)⌉`, which bounds the relative error of any value
+/// reconstructed from its bucket to `ε`.
+#[derive(Debug, Clone)]
+pub(crate) struct DDSketch {
+    gamma_ln: f64,
+    bins: HashMap<i32, u64>,
+    zero_count: u64,
+}
+
+impl DDSketch {
+    fn new(relative_accuracy: f64) -> Self {
+        let gamma = (1.0 + relative_accuracy) / (1.0 - relative_accuracy);
+        Self {
+            gamma_ln: gamma.ln(),
+            bins: HashMap::new(),
+            zero_count: 0,
+        }
+    }
+
+    /// Add a single non-negative observation (e.g. a span duration in
+    /// nanoseconds) to the distribution.
+    pub(crate) fn add(&mut self, value: f64) {
+        if value <= 0.0 {
+            self.zero_count += 1;
+            return;
+        }
+        let index = (value.ln() / self.gamma_ln).ceil() as i32;
+        *self.bins.entry(index).or_insert(0) += 1;
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        write_varint_field(buf, 1, self.zero_count);
+        for (index, count) in &self.bins {
+            let mut entry = Vec::new();
+            write_zigzag_field(&mut entry, 1, *index as i64);
+            write_varint_field(&mut entry, 2, *count);
+            write_tag(buf, 2, 2);
+            write_varint(buf, entry.len() as u64);
+            buf.extend_from_slice(&entry);
+        }
+    }
+}
+
+/// The dimensions Datadog groups APM stats by.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct StatsGroupKey {
+    service: String,
+    operation: String,
+    resource: String,
+    span_type: String,
+    http_status_code: u16,
+    error: bool,
+}
+
+#[derive(Debug, Clone)]
+struct StatsGroup {
+    key: StatsGroupKey,
+    hits: u64,
+    errors: u64,
+    top_level_hits: u64,
+    duration: DDSketch,
+    error_duration: DDSketch,
+}
+
+impl StatsGroup {
+    fn new(key: StatsGroupKey) -> Self {
+        Self {
+            key,
+            hits: 0,
+            errors: 0,
+            top_level_hits: 0,
+            duration: DDSketch::new(SKETCH_RELATIVE_ACCURACY),
+            error_duration: DDSketch::new(SKETCH_RELATIVE_ACCURACY),
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        write_string_field(buf, 1, &self.key.service);
+        write_string_field(buf, 2, &self.key.operation);
+        write_string_field(buf, 3, &self.key.resource);
+        write_string_field(buf, 4, &self.key.span_type);
+        write_varint_field(buf, 5, self.key.http_status_code as u64);
+        write_varint_field(buf, 6, self.hits);
+        write_varint_field(buf, 7, self.errors);
+        write_varint_field(buf, 8, self.top_level_hits);
+
+        let mut duration_buf = Vec::new();
+        self.duration.encode(&mut duration_buf);
+        write_tag(buf, 9, 2);
+        write_varint(buf, duration_buf.len() as u64);
+        buf.extend_from_slice(&duration_buf);
+
+        let mut error_duration_buf = Vec::new();
+        self.error_duration.encode(&mut error_duration_buf);
+        write_tag(buf, 10, 2);
+        write_varint(buf, error_duration_buf.len() as u64);
+        buf.extend_from_slice(&error_duration_buf);
+    }
+}
+
+#[derive(Debug)]
+struct StatsBucket {
+    start: u64,
+    groups: HashMap<StatsGroupKey, StatsGroup>,
+}
+
+impl StatsBucket {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        write_varint_field(buf, 1, self.start);
+        write_varint_field(buf, 2, BUCKET_DURATION.as_nanos() as u64);
+        for group in self.groups.values() {
+            let mut group_buf = Vec::new();
+            group.encode(&mut group_buf);
+            write_tag(buf, 3, 2);
+            write_varint(buf, group_buf.len() as u64);
+            buf.extend_from_slice(&group_buf);
+        }
+    }
+}
+
+/// Buckets spans by `(service, operation, resource, type, http status,
+/// error)` into aligned 10s windows, accumulating hit/error counts and
+/// latency distributions for each group.
+#[derive(Debug, Default)]
+pub(crate) struct StatsAggregator {
+    buckets: HashMap<u64, StatsBucket>,
+}
+
+impl StatsAggregator {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fold a batch of spans into the aggregator's current buckets.
+    ///
+    /// Groups by the same obfuscated resource name used for the span export
+    /// itself, so an unobfuscated query or command doesn't leak through the
+    /// stats channel or fragment cardinality by literal value.
+    pub(crate) fn add_spans(
+        &mut self,
+        service_name: &str,
+        spans: &[SpanData],
+        obfuscator: Option<&Obfuscator>,
+    ) {
+        for span in spans {
+            let start_ns = span
+                .start_time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64;
+            let bucket_ns = BUCKET_DURATION.as_nanos() as u64;
+            let bucket_start = (start_ns / bucket_ns) * bucket_ns;
+
+            let http_status_code = span
+                .attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == "http.status_code")
+                .and_then(|kv| match &kv.value {
+                    opentelemetry::api::Value::I64(code) => Some(*code as u16),
+                    _ => None,
+                })
+                .unwrap_or(0);
+            let span_type = model::span_type(span);
+            let error = span.status_code == opentelemetry::api::trace::StatusCode::Error;
+            let duration_ns = span
+                .end_time
+                .duration_since(span.start_time)
+                .unwrap_or_default()
+                .as_nanos() as f64;
+
+            let key = StatsGroupKey {
+                service: service_name.to_string(),
+                operation: "opentelemetry".to_string(),
+                resource: model::resource_name(span, obfuscator),
+                span_type,
+                http_status_code,
+                error,
+            };
+
+            let bucket = self
+                .buckets
+                .entry(bucket_start)
+                .or_insert_with(|| StatsBucket {
+                    start: bucket_start,
+                    groups: HashMap::new(),
+                });
+            let group = bucket
+                .groups
+                .entry(key.clone())
+                .or_insert_with(|| StatsGroup::new(key));
+
+            group.hits += 1;
+            // A span only counts as top-level here when it's the literal
+            // trace root (no parent span id at all). That undercounts any
+            // downstream service's local root, which has a real, remote
+            // parent id from the service that called it; attributing those
+            // correctly would mean tracking which service owns each parent
+            // span, which this aggregator doesn't do.
+            if span.parent_span_id.to_u64() == 0 {
+                group.top_level_hits += 1;
+            }
+            group.duration.add(duration_ns);
+            if error {
+                group.errors += 1;
+                group.error_duration.add(duration_ns);
+            }
+        }
+    }
+
+    /// Encode and remove the buckets whose 10s window has fully elapsed as
+    /// of `now_ns`, ready to be sent to the stats intake as a request
+    /// separate from the span export. Buckets still within their window are
+    /// left in place, so a single bucket is never split across flushes.
+    ///
+    /// Returns `None` when no bucket has closed yet.
+    pub(crate) fn flush_closed(&mut self, now_ns: u64) -> Option<Vec<u8>> {
+        let bucket_ns = BUCKET_DURATION.as_nanos() as u64;
+        let closed_starts: Vec<u64> = self
+            .buckets
+            .keys()
+            .copied()
+            .filter(|start| start.saturating_add(bucket_ns) <= now_ns)
+            .collect();
+        if closed_starts.is_empty() {
+            return None;
+        }
+
+        let mut payload = Vec::new();
+        for start in closed_starts {
+            let bucket = self.buckets.remove(&start)?;
+            let mut bucket_buf = Vec::new();
+            bucket.encode(&mut bucket_buf);
+            write_tag(&mut payload, 1, 2);
+            write_varint(&mut payload, bucket_buf.len() as u64);
+            payload.extend_from_slice(&bucket_buf);
+        }
+        Some(payload)
+    }
+}
+
+fn write_tag(buf: &mut Vec<u8>, field: u32, wire_type: u8) {
+    write_varint(buf, ((field << 3) | wire_type as u32) as u64);
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            break;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn write_varint_field(buf: &mut Vec<u8>, field: u32, value: u64) {
+    write_tag(buf, field, 0);
+    write_varint(buf, value);
+}
+
+fn write_zigzag_field(buf: &mut Vec<u8>, field: u32, value: i64) {
+    let zigzag = ((value << 1) ^ (value >> 63)) as u64;
+    write_varint_field(buf, field, zigzag);
+}
+
+fn write_string_field(buf: &mut Vec<u8>, field: u32, value: &str) {
+    write_tag(buf, field, 2);
+    write_varint(buf, value.len() as u64);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ddsketch_groups_identical_values_into_one_bucket() {
+        let mut sketch = DDSketch::new(SKETCH_RELATIVE_ACCURACY);
+        sketch.add(0.0);
+        sketch.add(100.0);
+        sketch.add(100.0);
+
+        assert_eq!(sketch.zero_count, 1);
+        assert_eq!(sketch.bins.len(), 1);
+        assert_eq!(sketch.bins.values().sum::<u64>(), 2);
+    }
+
+    #[test]
+    fn ddsketch_separates_values_outside_relative_accuracy() {
+        let mut sketch = DDSketch::new(SKETCH_RELATIVE_ACCURACY);
+        sketch.add(1.0);
+        sketch.add(1_000.0);
+
+        assert_eq!(sketch.bins.len(), 2);
+    }
+
+    #[test]
+    fn flush_closed_leaves_still_open_buckets_in_place() {
+        let mut aggregator = StatsAggregator::new();
+        let bucket_ns = BUCKET_DURATION.as_nanos() as u64;
+        let closed_start = 0u64;
+        let open_start = bucket_ns * 5;
+        for start in [closed_start, open_start] {
+            aggregator.buckets.insert(
+                start,
+                StatsBucket {
+                    start,
+                    groups: HashMap::new(),
+                },
+            );
+        }
+
+        // `now` lands exactly on the closed bucket's window boundary, well
+        // before the open bucket's window has elapsed.
+        let payload = aggregator.flush_closed(closed_start + bucket_ns);
+
+        assert!(payload.is_some());
+        assert_eq!(aggregator.buckets.len(), 1);
+        assert!(aggregator.buckets.contains_key(&open_start));
+    }
+
+    #[test]
+    fn flush_closed_returns_none_when_no_bucket_has_closed() {
+        let mut aggregator = StatsAggregator::new();
+        let bucket_ns = BUCKET_DURATION.as_nanos() as u64;
+        aggregator.buckets.insert(
+            0,
+            StatsBucket {
+                start: 0,
+                groups: HashMap::new(),
+            },
+        );
+
+        assert!(aggregator.flush_closed(bucket_ns - 1).is_none());
+    }
+}